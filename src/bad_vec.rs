@@ -1,7 +1,18 @@
 use crate::exec_alloc::*;
 use core::ops::{Deref, DerefMut};
 use core::ptr::NonNull;
-use core::{fmt, ptr, slice};
+use core::{fmt, mem, ptr, slice};
+
+/// An allocation failed: the OS refused to map the requested pages (or make them executable), or
+/// the requested size overflowed `usize` once rounded up to a whole number of pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError;
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("failed to allocate executable memory")
+    }
+}
 
 /// This is basically a bad version of `Vec` that doesn't require `Vec::new_in` to be stabilized.
 #[derive(PartialEq, Eq)]
@@ -11,37 +22,422 @@ pub struct ExecutableMemory {
     len: usize,
 }
 
-impl ExecutableMemory {
+/// A read+write (but not executable) region of memory, used to emit code before sealing it with
+/// [`make_executable`](Self::make_executable).
+///
+/// Splitting allocation from mutation like this keeps the underlying pages W^X (write xor
+/// execute) the entire time they're mapped: writable-but-not-executable here, then
+/// executable-but-not-writable once converted to an [`ExecutableMemory`]. Hardened platforms
+/// (Apple Silicon/iOS, OpenBSD, SELinux/PaX, hardened Android) reject RWX mappings outright, so
+/// this is the only portable way to emit code on them. See the `rwx` feature for the old
+/// unconditional-RWX behavior on platforms that still allow it.
+#[derive(PartialEq, Eq)]
+pub struct WritableMemory {
+    // NOTE: `slice.len()` is the *capacity* of the allocated memory. it may be uninitialized.
+    slice: NonNull<[u8]>,
+    len: usize,
+}
+
+impl WritableMemory {
     #[inline]
-    /// Return a new region of executable memory.
+    /// Return a new writable region of memory.
     ///
-    /// The region will be at least `desired_size` bytes large, but may be larger if `desired_size` is not
-    /// a multiple of the page size.
+    /// The region is exactly `desired_size` bytes long ([`len`](Self::len)); the allocation behind
+    /// it is rounded up to a whole number of pages, so [`capacity`](Self::capacity) may be larger.
     /// The memory returned will be initialized, but its contents is not specified.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the allocation fails. See [`try_new`](Self::try_new) for a fallible version.
     pub fn new(desired_size: usize) -> Self {
-        let slice = alloc_executable_memory(desired_size).expect("failed to allocate memory");
-        // SAFETY: `mmap` zero-inits memory
-        ExecutableMemory {
+        Self::try_new(desired_size).expect("failed to allocate memory")
+    }
+
+    /// Fallible version of [`new`](Self::new).
+    pub fn try_new(desired_size: usize) -> Result<Self, AllocError> {
+        let slice = try_alloc_writable_memory(desired_size).map_err(|()| AllocError)?;
+        // `len` is the *requested* size, not `slice.len()` (the rounded-up capacity): otherwise
+        // `push`/`reserve`/`resize` would start appending past up to a page of unused bytes
+        // instead of right after what the caller asked for.
+        Ok(WritableMemory {
+            slice,
+            len: desired_size,
+        })
+    }
+
+    /// Return a writable region of memory set to the contents of `data`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the allocation fails. See [`try_with_contents`](Self::try_with_contents) for a
+    /// fallible version.
+    pub fn with_contents(data: &[u8]) -> Self {
+        Self::try_with_contents(data).expect("failed to allocate memory")
+    }
+
+    /// Fallible version of [`with_contents`](Self::with_contents).
+    pub fn try_with_contents(data: &[u8]) -> Result<Self, AllocError> {
+        unsafe {
+            let slice = try_alloc_writable_memory(data.len()).map_err(|()| AllocError)?;
+            // SAFETY: `alloc_writable_memory` guarantees it returns a new memory allocation, so these don't overlap.
+            // it also guarantees `slice` is at least `data.len()` and aligned.
+            // rust's safety guarantees ensure `data.ptr()` and `data.len()` are aligned and accurate.
+            ptr::copy_nonoverlapping(data.as_ptr(), slice.as_ptr().cast(), data.len());
+            Ok(WritableMemory {
+                slice,
+                len: data.len(),
+            })
+        }
+    }
+
+    /// Return a new writable region of memory placed within `i32::MIN..=i32::MAX` bytes of
+    /// `target`.
+    ///
+    /// This is for JIT code that patches relative `call`/`jmp`/`adrp` instructions into `target`:
+    /// those encodings only reach a limited displacement from the instruction, so the code they
+    /// call into must be placed nearby. See [`make_executable`](Self::make_executable) to finish
+    /// building such a region.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no region within range of `target` could be allocated. See
+    /// [`try_new_near`](Self::try_new_near) for a fallible version.
+    pub fn new_near(desired_size: usize, target: *const u8) -> Self {
+        Self::try_new_near(desired_size, target).expect("failed to allocate memory near target")
+    }
+
+    /// Fallible version of [`new_near`](Self::new_near).
+    pub fn try_new_near(desired_size: usize, target: *const u8) -> Result<Self, AllocError> {
+        let slice = alloc_writable_memory_near(desired_size, target).map_err(|()| AllocError)?;
+        // See the `len` note in `try_new`: it's the requested size, not the rounded-up capacity.
+        Ok(WritableMemory {
             slice,
-            len: slice.len(),
+            len: desired_size,
+        })
+    }
+
+    #[inline(always)]
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.slice.as_ptr().cast()
+    }
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// Return the number of bytes this allocation can hold without growing.
+    #[inline(always)]
+    pub fn capacity(&self) -> usize {
+        self.slice.len()
+    }
+
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe {
+            // SAFETY: `slice` and `len` cannot be modified outside this module, and both `new` and
+            // `with_contents` guarantee that `len` bytes of `slice` are initialized.
+            // this slice cannot be mutated: the only way to mutate is through `as_slice_mut`, which takes `&mut self`.
+            slice::from_raw_parts(self.as_ptr(), self.len)
+        }
+    }
+    #[inline]
+    pub fn as_slice_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            // SAFETY: `&mut self` guarantees we don't have two slices at once.
+            // theoretically someone could call `unsafe { *mem.as_ptr() = x }` but that's on them to uphold the safety guarantees.
+            slice::from_raw_parts_mut(self.as_ptr(), self.len)
+        }
+    }
+
+    /// Ensure there is room for at least `additional` more bytes beyond `len`, growing the
+    /// allocation if necessary.
+    ///
+    /// Like [`Vec::reserve`](alloc::vec::Vec::reserve), this may allocate more than strictly
+    /// needed to amortize the cost of repeated growth. `as_ptr`/`as_slice` may return a different
+    /// pointer after this call.
+    pub fn reserve(&mut self, additional: usize) {
+        let required = self.len.checked_add(additional).expect("capacity overflow");
+        if required <= self.capacity() {
+            return;
+        }
+        // NOTE: mirrors `RawVec`'s doubling growth strategy; `saturating_mul` avoids overflow when
+        // `capacity()` is already near `usize::MAX / 2`.
+        let new_cap = core::cmp::max(required, self.capacity().saturating_mul(2));
+        self.grow_to(new_cap);
+    }
+
+    /// Resize the used length to `new_len`, filling any newly-exposed bytes with `fill`.
+    ///
+    /// Growing may allocate; shrinking just truncates `len` without freeing anything.
+    pub fn resize(&mut self, new_len: usize, fill: u8) {
+        if new_len > self.len {
+            self.reserve(new_len - self.len);
+            unsafe {
+                // SAFETY: `reserve` just grew `capacity` to at least `new_len`.
+                ptr::write_bytes(self.as_ptr().add(self.len), fill, new_len - self.len);
+            }
+        }
+        self.len = new_len;
+    }
+
+    /// Append a single byte to the end, growing the allocation if necessary.
+    pub fn push(&mut self, byte: u8) {
+        self.reserve(1);
+        unsafe {
+            // SAFETY: `reserve` just grew `capacity` to at least `len + 1`.
+            ptr::write(self.as_ptr().add(self.len), byte);
+        }
+        self.len += 1;
+    }
+
+    /// Grow the backing allocation to at least `new_cap` bytes, preserving the first `len` bytes.
+    fn grow_to(&mut self, new_cap: usize) {
+        let new_cap = round_to(new_cap, page_size());
+        let old_cap = self.capacity();
+        unsafe {
+            // SAFETY: `self.as_ptr()` was allocated by `alloc_writable_memory` and is `old_cap` bytes.
+            if let Some(new_slice) = remap_executable_memory(self.as_ptr(), old_cap, new_cap) {
+                self.slice = new_slice;
+                return;
+            }
+        }
+        let new_slice = alloc_writable_memory(new_cap).expect("failed to allocate memory");
+        unsafe {
+            // SAFETY: `new_slice` was just allocated and is disjoint from `self.slice`; it is at
+            // least `new_cap >= self.len` bytes.
+            ptr::copy_nonoverlapping(self.as_ptr(), new_slice.as_ptr().cast(), self.len);
+            // SAFETY: `self.as_ptr()` was allocated by `alloc_writable_memory` and is `old_cap` bytes.
+            dealloc_executable_memory(self.as_ptr(), old_cap);
         }
+        self.slice = new_slice;
+    }
+
+    /// Seal this region read+execute and hand back an [`ExecutableMemory`] handle.
+    ///
+    /// After this call the memory can no longer be written to: sealing only grants
+    /// `PROT_READ | PROT_EXEC`, never write, matching the W^X policy most hardened platforms now
+    /// enforce. Call this only once code has been fully emitted.
+    ///
+    /// This also synchronizes the instruction cache (see
+    /// [`ExecutableMemory::flush_instruction_cache`]), so the returned handle is safe to execute
+    /// immediately on every supported architecture.
+    ///
+    /// # Panics
+    ///
+    /// Panics if sealing fails. See [`try_make_executable`](Self::try_make_executable) for a
+    /// fallible version.
+    pub fn make_executable(self) -> ExecutableMemory {
+        self.try_make_executable()
+            .expect("failed to make memory executable")
+    }
+
+    /// Fallible version of [`make_executable`](Self::make_executable).
+    pub fn try_make_executable(self) -> Result<ExecutableMemory, AllocError> {
+        let cap = self.capacity();
+        unsafe {
+            // SAFETY: `self.as_ptr()` was allocated by `alloc_writable_memory` and `cap` is its
+            // exact capacity; `mprotect`/`VirtualProtect` round to whole pages so the full
+            // capacity (not just `len`) must be passed.
+            seal_executable(self.as_ptr(), cap).map_err(|()| AllocError)?;
+        }
+        let mem = ExecutableMemory {
+            slice: self.slice,
+            len: self.len,
+        };
+        // NOTE: ownership of the mapping moves to `mem`; don't let `self`'s `Drop` free it too.
+        mem::forget(self);
+        mem.flush_instruction_cache();
+        Ok(mem)
+    }
+}
+
+impl Deref for WritableMemory {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        self.as_slice()
+    }
+}
+impl DerefMut for WritableMemory {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.as_slice_mut()
+    }
+}
+
+impl fmt::Debug for WritableMemory {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_slice().fmt(f)
+    }
+}
+
+impl Drop for WritableMemory {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe {
+            dealloc_executable_memory(self.as_ptr(), self.slice.len());
+        }
+    }
+}
+
+impl ExecutableMemory {
+    /// Return a new region of executable memory.
+    ///
+    /// The region is exactly `desired_size` bytes long ([`len`](Self::len)); the allocation behind
+    /// it is rounded up to a whole number of pages, so [`capacity`](Self::capacity) may be larger.
+    /// The memory returned will be initialized, but its contents is not specified.
+    ///
+    /// This allocates the region write-then-seal (see [`WritableMemory`]) so it never holds a
+    /// writable+executable mapping at once. To emit code before sealing it, build a
+    /// [`WritableMemory`] directly and call [`WritableMemory::make_executable`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the allocation fails. See [`try_new`](Self::try_new) for a fallible version.
+    #[cfg(not(feature = "rwx"))]
+    #[inline]
+    pub fn new(desired_size: usize) -> Self {
+        Self::try_new(desired_size).expect("failed to allocate memory")
+    }
+
+    /// Fallible version of [`new`](Self::new).
+    #[cfg(not(feature = "rwx"))]
+    #[inline]
+    pub fn try_new(desired_size: usize) -> Result<Self, AllocError> {
+        WritableMemory::try_new(desired_size)?.try_make_executable()
     }
 
     /// Return a region of executable memory set to the contents of `data`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the allocation fails. See [`try_with_contents`](Self::try_with_contents) for a
+    /// fallible version.
+    #[cfg(not(feature = "rwx"))]
+    #[inline]
+    pub fn with_contents(data: &[u8]) -> Self {
+        Self::try_with_contents(data).expect("failed to allocate memory")
+    }
+
+    /// Fallible version of [`with_contents`](Self::with_contents).
+    #[cfg(not(feature = "rwx"))]
+    #[inline]
+    pub fn try_with_contents(data: &[u8]) -> Result<Self, AllocError> {
+        WritableMemory::try_with_contents(data)?.try_make_executable()
+    }
+
+    /// Return a new region of executable memory placed within `i32::MIN..=i32::MAX` bytes of
+    /// `target`, for JIT code that patches relative `call`/`jmp`/`adrp` instructions into
+    /// `target`. See [`WritableMemory::new_near`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if no region within range of `target` could be allocated. See
+    /// [`try_new_near`](Self::try_new_near) for a fallible version.
+    #[cfg(not(feature = "rwx"))]
+    #[inline]
+    pub fn new_near(desired_size: usize, target: *const u8) -> Self {
+        Self::try_new_near(desired_size, target).expect("failed to allocate memory near target")
+    }
+
+    /// Fallible version of [`new_near`](Self::new_near).
+    #[cfg(not(feature = "rwx"))]
+    #[inline]
+    pub fn try_new_near(desired_size: usize, target: *const u8) -> Result<Self, AllocError> {
+        WritableMemory::try_new_near(desired_size, target)?.try_make_executable()
+    }
+
+    /// Return a new region of executable memory, mapped read+write+execute all at once.
+    ///
+    /// Requires the `rwx` feature: most hardened platforms (Apple Silicon/iOS, OpenBSD,
+    /// SELinux/PaX, hardened Android) reject RWX mappings outright. Prefer
+    /// [`WritableMemory::new`] + [`WritableMemory::make_executable`] unless you know RWX mappings
+    /// are allowed on your target.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the allocation fails. See [`try_new`](Self::try_new) for a fallible version.
+    #[cfg(feature = "rwx")]
+    #[inline]
+    pub fn new(desired_size: usize) -> Self {
+        Self::try_new(desired_size).expect("failed to allocate memory")
+    }
+
+    /// Fallible version of [`new`](Self::new).
+    #[cfg(feature = "rwx")]
+    #[inline]
+    pub fn try_new(desired_size: usize) -> Result<Self, AllocError> {
+        let slice = try_alloc_executable_memory(desired_size).map_err(|()| AllocError)?;
+        // `len` is the requested size, not `slice.len()` (the rounded-up capacity): otherwise
+        // `push`/`reserve`/`resize` would start appending past up to a page of unused bytes
+        // instead of right after what the caller asked for.
+        Ok(ExecutableMemory {
+            slice,
+            len: desired_size,
+        })
+    }
+
+    /// Return a region of executable memory set to the contents of `data`, mapped RWX.
+    ///
+    /// Requires the `rwx` feature; see [`Self::new`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the allocation fails. See [`try_with_contents`](Self::try_with_contents) for a
+    /// fallible version.
+    #[cfg(feature = "rwx")]
     pub fn with_contents(data: &[u8]) -> Self {
+        Self::try_with_contents(data).expect("failed to allocate memory")
+    }
+
+    /// Fallible version of [`with_contents`](Self::with_contents).
+    #[cfg(feature = "rwx")]
+    pub fn try_with_contents(data: &[u8]) -> Result<Self, AllocError> {
         unsafe {
-            let slice = alloc_executable_memory(data.len()).expect("failed to allocate memory");
+            let slice = try_alloc_executable_memory(data.len()).map_err(|()| AllocError)?;
             // SAFETY: `alloc_executable_memory` guarantees it returns a new memory allocation, so these don't overlap.
             // it also guarantees `slice` is at least `data.len()` and aligned.
             // rust's safety guarantees ensure `data.ptr()` and `data.len()` are aligned and accurate.
             ptr::copy_nonoverlapping(data.as_ptr(), slice.as_ptr().cast(), data.len());
-            ExecutableMemory {
+            Ok(ExecutableMemory {
                 slice,
                 len: data.len(),
-            }
+            })
         }
     }
 
+    /// Return a new region of executable memory, mapped RWX, placed within
+    /// `i32::MIN..=i32::MAX` bytes of `target`. See [`WritableMemory::new_near`].
+    ///
+    /// Requires the `rwx` feature; see [`Self::new`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if no region within range of `target` could be allocated. See
+    /// [`try_new_near`](Self::try_new_near) for a fallible version.
+    #[cfg(feature = "rwx")]
+    #[inline]
+    pub fn new_near(desired_size: usize, target: *const u8) -> Self {
+        Self::try_new_near(desired_size, target).expect("failed to allocate memory near target")
+    }
+
+    /// Fallible version of [`new_near`](Self::new_near).
+    #[cfg(feature = "rwx")]
+    #[inline]
+    pub fn try_new_near(desired_size: usize, target: *const u8) -> Result<Self, AllocError> {
+        let slice = alloc_executable_memory_near(desired_size, target).map_err(|()| AllocError)?;
+        // See the `len` note in `try_new`: it's the requested size, not the rounded-up capacity.
+        Ok(ExecutableMemory {
+            slice,
+            len: desired_size,
+        })
+    }
+
     #[inline(always)]
     pub fn as_ptr(&self) -> *mut u8 {
         self.slice.as_ptr().cast()
@@ -54,6 +450,11 @@ impl ExecutableMemory {
     pub fn is_empty(&self) -> bool {
         self.len == 0
     }
+    /// Return the number of bytes this allocation can hold without growing.
+    #[inline(always)]
+    pub fn capacity(&self) -> usize {
+        self.slice.len()
+    }
 
     #[inline]
     pub fn as_slice(&self) -> &[u8] {
@@ -64,6 +465,34 @@ impl ExecutableMemory {
             slice::from_raw_parts(self.as_ptr(), self.len)
         }
     }
+
+    /// Synchronize the instruction cache over this region, so code written to it is safe to
+    /// execute.
+    ///
+    /// On x86/x86-64 this is a no-op: those architectures guarantee coherent instruction and data
+    /// caches. On ARM/AArch64/RISC-V the two caches are not coherent, so bytes written through
+    /// [`as_slice_mut`](Self::as_slice_mut) (under the `rwx` feature) are not guaranteed to be
+    /// visible to the CPU's instruction fetch path until this is called. Callers must call this
+    /// after the last write and before transmuting a pointer into this region to a function
+    /// pointer.
+    ///
+    /// [`WritableMemory::make_executable`] already calls this once for you, and the region it
+    /// returns is sealed against writes, so it's only needed for regions built by the `rwx`
+    /// constructors (`new`/`with_contents`/`new_near` under the `rwx` feature), which map RWX and
+    /// never call it: after `with_contents` and after every later write.
+    #[inline]
+    pub fn flush_instruction_cache(&self) {
+        unsafe {
+            // SAFETY: `as_ptr()` is valid for `len()` bytes per this type's own invariants.
+            crate::exec_alloc::flush_instruction_cache(self.as_ptr(), self.len());
+        }
+    }
+
+    /// Mutable access to the underlying bytes.
+    ///
+    /// Only available under the `rwx` feature: without it, this region is sealed read+execute
+    /// and can't be written to. Use [`WritableMemory`] to emit code before sealing instead.
+    #[cfg(feature = "rwx")]
     #[inline]
     pub fn as_slice_mut(&mut self) -> &mut [u8] {
         unsafe {
@@ -72,6 +501,69 @@ impl ExecutableMemory {
             slice::from_raw_parts_mut(self.as_ptr(), self.len)
         }
     }
+
+    /// Ensure there is room for at least `additional` more bytes beyond `len`, growing the
+    /// allocation if necessary. Requires the `rwx` feature; see [`Self::as_slice_mut`].
+    #[cfg(feature = "rwx")]
+    pub fn reserve(&mut self, additional: usize) {
+        let required = self.len.checked_add(additional).expect("capacity overflow");
+        if required <= self.capacity() {
+            return;
+        }
+        // NOTE: mirrors `RawVec`'s doubling growth strategy; `saturating_mul` avoids overflow when
+        // `capacity()` is already near `usize::MAX / 2`.
+        let new_cap = core::cmp::max(required, self.capacity().saturating_mul(2));
+        self.grow_to(new_cap);
+    }
+
+    /// Resize the used length to `new_len`, filling any newly-exposed bytes with `fill`. Requires
+    /// the `rwx` feature; see [`Self::as_slice_mut`].
+    #[cfg(feature = "rwx")]
+    pub fn resize(&mut self, new_len: usize, fill: u8) {
+        if new_len > self.len {
+            self.reserve(new_len - self.len);
+            unsafe {
+                // SAFETY: `reserve` just grew `capacity` to at least `new_len`.
+                ptr::write_bytes(self.as_ptr().add(self.len), fill, new_len - self.len);
+            }
+        }
+        self.len = new_len;
+    }
+
+    /// Append a single byte to the end, growing the allocation if necessary. Requires the `rwx`
+    /// feature; see [`Self::as_slice_mut`].
+    #[cfg(feature = "rwx")]
+    pub fn push(&mut self, byte: u8) {
+        self.reserve(1);
+        unsafe {
+            // SAFETY: `reserve` just grew `capacity` to at least `len + 1`.
+            ptr::write(self.as_ptr().add(self.len), byte);
+        }
+        self.len += 1;
+    }
+
+    /// Grow the backing allocation to at least `new_cap` bytes, preserving the first `len` bytes.
+    #[cfg(feature = "rwx")]
+    fn grow_to(&mut self, new_cap: usize) {
+        let new_cap = round_to(new_cap, page_size());
+        let old_cap = self.capacity();
+        unsafe {
+            // SAFETY: `self.as_ptr()` was allocated by `alloc_executable_memory` and is `old_cap` bytes.
+            if let Some(new_slice) = remap_executable_memory(self.as_ptr(), old_cap, new_cap) {
+                self.slice = new_slice;
+                return;
+            }
+        }
+        let new_slice = alloc_executable_memory(new_cap).expect("failed to allocate memory");
+        unsafe {
+            // SAFETY: `new_slice` was just allocated and is disjoint from `self.slice`; it is at
+            // least `new_cap >= self.len` bytes.
+            ptr::copy_nonoverlapping(self.as_ptr(), new_slice.as_ptr().cast(), self.len);
+            // SAFETY: `self.as_ptr()` was allocated by `alloc_executable_memory` and is `old_cap` bytes.
+            dealloc_executable_memory(self.as_ptr(), old_cap);
+        }
+        self.slice = new_slice;
+    }
 }
 
 impl Deref for ExecutableMemory {
@@ -82,6 +574,7 @@ impl Deref for ExecutableMemory {
         self.as_slice()
     }
 }
+#[cfg(feature = "rwx")]
 impl DerefMut for ExecutableMemory {
     #[inline]
     fn deref_mut(&mut self) -> &mut Self::Target {
@@ -109,25 +602,96 @@ impl Drop for ExecutableMemory {
 mod test {
     use super::*;
 
+    #[test]
+    fn new_near_is_within_range_of_target() {
+        let target = new_near_is_within_range_of_target as *const u8;
+        let memory = WritableMemory::new_near(64, target);
+        let start = memory.as_ptr() as isize - target as isize;
+        let end = (memory.as_ptr() as isize + memory.len() as isize) - target as isize;
+        assert!((i32::MIN as isize..=i32::MAX as isize).contains(&start));
+        assert!((i32::MIN as isize..=i32::MAX as isize).contains(&end));
+    }
+
+    #[test]
+    fn flush_instruction_cache_is_safe_after_make_executable() {
+        // `make_executable` already calls this once; calling it again should be harmless, and on
+        // x86/x86-64 it's a no-op either way.
+        let memory = WritableMemory::new(6).make_executable();
+        memory.flush_instruction_cache();
+    }
+
     #[test]
     fn test_call_function() {
-        let mut memory = ExecutableMemory::new(1);
+        let mut code = WritableMemory::new(6);
 
-        memory[0] = 0xb8;
-        memory[1] = 0xff;
-        memory[2] = 0xff;
-        memory[3] = 0xff;
-        memory[4] = 0xff;
-        memory[5] = 0xc3;
+        code[0] = 0xb8;
+        code[1] = 0xff;
+        code[2] = 0xff;
+        code[3] = 0xff;
+        code[4] = 0xff;
+        code[5] = 0xc3;
 
+        let memory = code.make_executable();
         let f: fn() -> u32 = unsafe { core::mem::transmute(memory[0..6].as_ptr()) };
 
         assert_eq!(f(), 4294967295);
     }
 
     #[test]
-    #[should_panic = "don't try to allocate usize::MAX lol"]
+    fn grow_past_page_boundary_preserves_contents() {
+        let page = page_size();
+        let mut code = WritableMemory::new(1);
+        let start = code.len();
+        for i in 0..page + 16 {
+            code.push((i % 251) as u8);
+        }
+        assert_eq!(code.len(), start + page + 16);
+        assert!(code.capacity() >= code.len());
+        for i in 0..page + 16 {
+            assert_eq!(code[start + i], (i % 251) as u8, "byte {i} corrupted by grow");
+        }
+    }
+
+    #[test]
+    fn grow_then_make_executable_and_call() {
+        let page = page_size();
+        let mut code = WritableMemory::new(1);
+        // push enough NOPs to force at least one grow beyond the initial page.
+        for _ in 0..page + 16 {
+            code.push(0x90);
+        }
+        code.push(0xb8);
+        code.push(0xff);
+        code.push(0xff);
+        code.push(0xff);
+        code.push(0xff);
+        code.push(0xc3);
+
+        let memory = code.make_executable();
+        let tail = &memory[memory.len() - 6..];
+        let f: fn() -> u32 = unsafe { core::mem::transmute(tail.as_ptr()) };
+
+        assert_eq!(f(), 4294967295);
+    }
+
+    #[cfg(all(feature = "rwx", feature = "nightly"))]
+    #[test]
+    fn over_aligned_allocation_is_aligned_and_frees() {
+        let align = page_size() * 4;
+        let slice = alloc_executable_memory_aligned(16, align).expect("over-aligned alloc failed");
+        assert_eq!(slice.as_ptr().cast::<u8>() as usize % align, 0);
+        unsafe {
+            dealloc_executable_memory_aligned(slice.as_ptr().cast(), 16, align);
+        }
+    }
+
+    #[test]
+    #[should_panic = "failed to allocate memory"]
     fn overflow() {
+        // `try_alloc_executable_memory`/`try_alloc_writable_memory` reject `desired >
+        // isize::MAX` up front with `Err(())`, before `round_to` ever gets a chance to overflow,
+        // so this panics via `new`'s `.expect("failed to allocate memory")` rather than via
+        // `round_to`.
         ExecutableMemory::new(usize::MAX);
     }
 }
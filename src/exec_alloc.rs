@@ -1,18 +1,27 @@
 use core::ptr;
 use core::ptr::NonNull;
 
-/// Round `desired` up to the nearest multiple of `page_size`.
-fn round_to(desired: usize, page_size: usize) -> usize {
+/// Round `desired` up to the nearest multiple of `page_size`, returning an error instead of
+/// panicking if doing so would overflow `usize`.
+fn try_round_to(desired: usize, page_size: usize) -> Result<usize, ()> {
     let rem = desired % page_size;
     if rem == 0 {
-        desired
+        Ok(desired)
     } else {
-        desired
-            .checked_add(page_size - rem)
-            .expect("don't try to allocate usize::MAX lol")
+        desired.checked_add(page_size - rem).ok_or(())
     }
 }
 
+/// Round `desired` up to the nearest multiple of `page_size`.
+pub(crate) fn round_to(desired: usize, page_size: usize) -> usize {
+    try_round_to(desired, page_size).expect("don't try to allocate usize::MAX lol")
+}
+
+/// Allocate `desired` bytes mapped RWX (read, write, *and* execute at once).
+///
+/// Hardened platforms (Apple Silicon/iOS, OpenBSD, SELinux/PaX) reject RWX mappings outright;
+/// prefer [`alloc_writable_memory`] + [`seal_executable`] unless the `rwx` feature is enabled.
+#[cfg(feature = "rwx")]
 pub(crate) fn alloc_executable_memory(desired: usize) -> Result<NonNull<[u8]>, ()> {
     // https://doc.rust-lang.org/std/alloc/struct.Layout.html
     assert!(
@@ -22,6 +31,223 @@ pub(crate) fn alloc_executable_memory(desired: usize) -> Result<NonNull<[u8]>, (
     impl_::alloc_executable_memory(desired)
 }
 
+/// Like [`alloc_executable_memory`], but returns `Err(())` instead of panicking when `desired`
+/// is too big (whether that's because it exceeds `isize::MAX` or because rounding it up to a
+/// whole number of pages would overflow `usize`).
+#[cfg(feature = "rwx")]
+pub(crate) fn try_alloc_executable_memory(desired: usize) -> Result<NonNull<[u8]>, ()> {
+    if desired > isize::MAX as usize {
+        return Err(());
+    }
+    impl_::alloc_executable_memory(desired)
+}
+
+/// Allocate `desired` bytes mapped read+write, but not executable.
+///
+/// Call [`seal_executable`] once the code has been written to make the region executable, per
+/// the W^X (write xor execute) policy most modern OSes enforce.
+pub(crate) fn alloc_writable_memory(desired: usize) -> Result<NonNull<[u8]>, ()> {
+    assert!(
+        desired <= isize::MAX as usize,
+        "alloc {desired} is too big; allocating more than isize::MAX is not allowed"
+    );
+    impl_::alloc_writable_memory(desired)
+}
+
+/// Like [`alloc_writable_memory`], but returns `Err(())` instead of panicking when `desired` is
+/// too big (whether that's because it exceeds `isize::MAX` or because rounding it up to a whole
+/// number of pages would overflow `usize`).
+pub(crate) fn try_alloc_writable_memory(desired: usize) -> Result<NonNull<[u8]>, ()> {
+    if desired > isize::MAX as usize {
+        return Err(());
+    }
+    impl_::alloc_writable_memory(desired)
+}
+
+/// Mark a previously-writable region read+execute, sealing it against further writes.
+///
+/// SAFETY: `ptr` must point to `cap` bytes allocated by [`alloc_writable_memory`]; `cap` must
+/// match the allocation's actual capacity exactly, since `mprotect`/`VirtualProtect` operate on
+/// whole pages and the allocation may be larger than what the caller asked for.
+pub(crate) unsafe fn seal_executable(ptr: *mut u8, cap: usize) -> Result<(), ()> {
+    impl_::seal_executable(ptr, cap)
+}
+
+/// A small record `alloc_executable_memory_aligned` stashes just before the pointer it returns,
+/// so `dealloc_executable_memory_aligned` can recover the true mmap/`VirtualAlloc` region to free
+/// (the pointer users see is rounded up to `align` and so is generally not the true base).
+#[cfg(all(feature = "rwx", feature = "nightly"))]
+#[repr(C)]
+struct AlignedAllocHeader {
+    base: *mut u8,
+    total: usize,
+}
+
+#[cfg(all(feature = "rwx", feature = "nightly"))]
+fn align_up(addr: usize, align: usize) -> Option<usize> {
+    addr.checked_add(align - 1).map(|rounded| rounded & !(align - 1))
+}
+
+/// Allocate `size` bytes mapped RWX, with the returned pointer satisfying `align` even when
+/// `align` exceeds the page size (e.g. for huge-page-backed JIT caches or aligned trampoline
+/// tables).
+///
+/// Over-allocates `size + align + size_of::<AlignedAllocHeader>()` bytes so there's always room
+/// both to round up to `align` and to stash a header recording the true allocation; see
+/// [`dealloc_executable_memory_aligned`].
+#[cfg(all(feature = "rwx", feature = "nightly"))]
+pub(crate) fn alloc_executable_memory_aligned(size: usize, align: usize) -> Result<NonNull<[u8]>, ()> {
+    debug_assert!(align.is_power_of_two());
+    if align <= page_size() {
+        return alloc_executable_memory(size);
+    }
+    let header_size = core::mem::size_of::<AlignedAllocHeader>();
+    let total = size
+        .checked_add(align)
+        .and_then(|n| n.checked_add(header_size))
+        .ok_or(())?;
+    let base = alloc_executable_memory(total)?;
+    let base_ptr: *mut u8 = base.as_ptr().cast();
+    let aligned = align_up(base_ptr as usize + header_size, align).ok_or(())?;
+    let aligned_ptr: *mut u8 = aligned as *mut u8;
+    unsafe {
+        // SAFETY: `align_up` never advances past `base_ptr as usize + header_size + align - 1`,
+        // which is within the `total` bytes we just allocated, so the header fits just before
+        // `aligned_ptr` and the full `size` bytes fit at and after it.
+        aligned_ptr
+            .cast::<AlignedAllocHeader>()
+            .sub(1)
+            .write(AlignedAllocHeader { base: base_ptr, total: base.len() });
+    }
+    let ptr = NonNull::new(aligned_ptr).ok_or(())?;
+    Ok(NonNull::slice_from_raw_parts(ptr, size))
+}
+
+/// Free memory returned by [`alloc_executable_memory_aligned`].
+///
+/// SAFETY: `ptr` must have been returned by `alloc_executable_memory_aligned(size, align)` with
+/// this exact `size` and `align`.
+#[cfg(all(feature = "rwx", feature = "nightly"))]
+pub(crate) unsafe fn dealloc_executable_memory_aligned(ptr: *mut u8, size: usize, align: usize) {
+    if align <= page_size() {
+        // no header was written: `ptr` is the true base from a plain `alloc_executable_memory`.
+        dealloc_executable_memory(ptr, round_to(size, page_size()));
+        return;
+    }
+    let header = ptr.cast::<AlignedAllocHeader>().sub(1).read();
+    dealloc_executable_memory(header.base, header.total);
+}
+
+/// Maximum distance, in bytes, a pointer may be from a `_near` allocation's `target` while still
+/// being reachable by a 32-bit relative `call`/`jmp`/`adrp` displacement.
+const NEAR_RANGE: i64 = i32::MAX as i64;
+
+/// Whether the whole `[ptr, ptr + len)` region is within `i32::MIN..=i32::MAX` bytes of `target`.
+///
+/// Both ends of the region must be checked: for a region of any real size, a base pointer near
+/// one edge of the range can have its far end (`ptr + len`) fall outside it even though the base
+/// itself passes.
+fn in_near_range(ptr: usize, len: usize, target: usize) -> bool {
+    let start_diff = ptr as i64 - target as i64;
+    let end_diff = (ptr as i64 + len as i64) - target as i64;
+    (-NEAR_RANGE - 1..=NEAR_RANGE).contains(&start_diff) && (-NEAR_RANGE - 1..=NEAR_RANGE).contains(&end_diff)
+}
+
+/// Number of nearby addresses `alloc_*_memory_near` probes before giving up.
+const NEAR_ALLOC_ATTEMPTS: usize = 64;
+
+/// The `attempt`th candidate address to hint the OS with when allocating near `target`, stepping
+/// outward in page-aligned increments that double every pair of attempts (starting at 1 MiB, one
+/// step below and one above `target` per doubling) and saturate at `NEAR_RANGE` once reached, so
+/// the full `i32::MIN..=i32::MAX` window gets covered rather than just its first few megabytes.
+fn near_candidate(target: usize, page_size: usize, attempt: usize) -> usize {
+    const FIRST_STEP: usize = 1 << 20;
+    let page_mask = !(page_size - 1);
+    if attempt == 0 {
+        return target & page_mask;
+    }
+    let doublings = (attempt - 1) / 2;
+    let magnitude = FIRST_STEP
+        .checked_shl(doublings as u32)
+        .unwrap_or(usize::MAX)
+        .min(NEAR_RANGE as usize);
+    if attempt % 2 == 1 {
+        target.saturating_sub(magnitude) & page_mask
+    } else {
+        target.saturating_add(magnitude) & page_mask
+    }
+}
+
+/// Allocate `desired` bytes mapped read+write, placed within `i32::MIN..=i32::MAX` bytes of
+/// `target` so relative `call`/`jmp`/`adrp` instructions patched between the two can reach,
+/// without needing a trampoline. This is the standard technique JITs use to guarantee short-form
+/// relocations.
+///
+/// Returns `Err(())` if `desired` is too big, or if no region within range of `target` could be
+/// found after probing nearby addresses.
+pub(crate) fn alloc_writable_memory_near(desired: usize, target: *const u8) -> Result<NonNull<[u8]>, ()> {
+    if desired > isize::MAX as usize {
+        return Err(());
+    }
+    impl_::alloc_writable_memory_near(desired, target)
+}
+
+/// Like [`alloc_writable_memory_near`], but maps the region RWX instead of read+write. Requires
+/// the `rwx` feature; see [`alloc_executable_memory`].
+#[cfg(feature = "rwx")]
+pub(crate) fn alloc_executable_memory_near(desired: usize, target: *const u8) -> Result<NonNull<[u8]>, ()> {
+    if desired > isize::MAX as usize {
+        return Err(());
+    }
+    impl_::alloc_executable_memory_near(desired, target)
+}
+
+/// Make code written to `[ptr, ptr + len)` visible to the CPU's instruction fetch path.
+///
+/// On architectures with coherent instruction and data caches (x86/x86-64) this is a no-op.
+/// Elsewhere the data and instruction caches can disagree about what's at a given address until
+/// the data cache is cleaned to the point of unification, the instruction cache is invalidated
+/// over the same range, and the appropriate `dsb`/`isb`-style barriers run — otherwise a CPU core
+/// may still execute stale instructions (or nothing at all) after `ExecutableMemory` is written
+/// to and sealed.
+///
+/// SAFETY: `ptr` must be valid for reads of `len` bytes.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(crate) unsafe fn flush_instruction_cache(_ptr: *const u8, _len: usize) {}
+
+// `__clear_cache` is a compiler-rt/libgcc builtin (what `__builtin___clear_cache` lowers to), not
+// a libc export, so it isn't in the `libc` crate's bindings; declare it ourselves.
+#[cfg(all(
+    any(target_arch = "arm", target_arch = "aarch64", target_arch = "riscv32", target_arch = "riscv64"),
+    any(target_os = "linux", target_os = "macos"),
+))]
+extern "C" {
+    fn __clear_cache(start: *mut core::ffi::c_char, end: *mut core::ffi::c_char);
+}
+
+/// SAFETY: `ptr` must be valid for reads of `len` bytes.
+#[cfg(all(
+    any(target_arch = "arm", target_arch = "aarch64", target_arch = "riscv32", target_arch = "riscv64"),
+    any(target_os = "linux", target_os = "macos"),
+))]
+pub(crate) unsafe fn flush_instruction_cache(ptr: *const u8, len: usize) {
+    // SAFETY: `__clear_cache` cleans the data cache and invalidates the instruction cache over
+    // `[ptr, end)` (and issues the `dsb`/`isb` barriers needed for other cores to observe it);
+    // the caller upholds that `ptr` is valid for `len` bytes, so `end` is in bounds.
+    let end = ptr.add(len);
+    __clear_cache(ptr as *mut core::ffi::c_char, end as *mut core::ffi::c_char);
+}
+
+/// SAFETY: `ptr` must be valid for reads of `len` bytes.
+#[cfg(all(any(target_arch = "arm", target_arch = "aarch64"), target_os = "windows"))]
+pub(crate) unsafe fn flush_instruction_cache(ptr: *const u8, len: usize) {
+    winapi::um::processthreadsapi::FlushInstructionCache(
+        winapi::um::processthreadsapi::GetCurrentProcess(),
+        ptr as *const _,
+        len,
+    );
+}
+
 pub(crate) use impl_::*;
 
 #[cfg(any(target_os = "linux", target_os = "macos"))]
@@ -37,34 +263,118 @@ mod unix {
         size as usize
     }
 
+    #[cfg(feature = "rwx")]
     pub(super) fn alloc_executable_memory(desired: usize) -> Result<NonNull<[u8]>, ()> {
-        let actual = round_to(desired, page_size());
-        unsafe {
-            let ptr = libc::mmap(
-                ptr::null_mut(),
-                actual,
-                libc::PROT_EXEC | libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
-                -1,
-                0,
-            );
+        let actual = try_round_to(desired, page_size())?;
+        unsafe { mmap_anon(actual, libc::PROT_EXEC | libc::PROT_READ | libc::PROT_WRITE) }
+    }
+
+    pub(super) fn alloc_writable_memory(desired: usize) -> Result<NonNull<[u8]>, ()> {
+        let actual = try_round_to(desired, page_size())?;
+        unsafe { mmap_anon(actual, libc::PROT_READ | libc::PROT_WRITE) }
+    }
+
+    pub(super) fn alloc_writable_memory_near(desired: usize, target: *const u8) -> Result<NonNull<[u8]>, ()> {
+        let actual = try_round_to(desired, page_size())?;
+        unsafe { mmap_anon_near(actual, libc::PROT_READ | libc::PROT_WRITE, target) }
+    }
+
+    #[cfg(feature = "rwx")]
+    pub(super) fn alloc_executable_memory_near(desired: usize, target: *const u8) -> Result<NonNull<[u8]>, ()> {
+        let actual = try_round_to(desired, page_size())?;
+        unsafe { mmap_anon_near(actual, libc::PROT_EXEC | libc::PROT_READ | libc::PROT_WRITE, target) }
+    }
+
+    unsafe fn mmap_anon(len: usize, prot: libc::c_int) -> Result<NonNull<[u8]>, ()> {
+        let ptr = libc::mmap(
+            ptr::null_mut(),
+            len,
+            prot,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if ptr == libc::MAP_FAILED {
+            Err(())
+        } else {
+            match NonNull::new(ptr.cast()) {
+                Some(ptr) => Ok(NonNull::slice_from_raw_parts(ptr, len)),
+                // NOTE: it's actually valid for mmap to point to the 0 address. but rust's allocator design rules this out.
+                // oops!
+                None => Err(()),
+            }
+        }
+    }
+
+    /// SAFETY: `ptr` must point to `cap` bytes previously mapped by `alloc_writable_memory`.
+    pub(super) unsafe fn seal_executable(ptr: *mut u8, cap: usize) -> Result<(), ()> {
+        if libc::mprotect(ptr as *mut _, cap, libc::PROT_READ | libc::PROT_EXEC) == 0 {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    /// `mmap` with an address hint near `target`, retrying at nearby page-aligned addresses
+    /// (since the kernel is free to ignore the hint and place the mapping wherever it likes)
+    /// until the result lands within [`in_near_range`] of `target`.
+    unsafe fn mmap_anon_near(len: usize, prot: libc::c_int, target: *const u8) -> Result<NonNull<[u8]>, ()> {
+        let page = page_size();
+        let target = target as usize;
+        for attempt in 0..NEAR_ALLOC_ATTEMPTS {
+            let hint = near_candidate(target, page, attempt) as *mut libc::c_void;
+            let ptr = libc::mmap(hint, len, prot, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0);
             if ptr == libc::MAP_FAILED {
-                Err(())
-            } else {
-                match NonNull::new(ptr.cast()) {
-                    Some(ptr) => Ok(NonNull::slice_from_raw_parts(ptr, actual)),
-                    // NOTE: it's actually valid for mmap to point to the 0 address. but rust's allocator design rules this out.
-                    // oops!
+                continue;
+            }
+            if in_near_range(ptr as usize, len, target) {
+                return match NonNull::new(ptr.cast()) {
+                    Some(ptr) => Ok(NonNull::slice_from_raw_parts(ptr, len)),
                     None => Err(()),
-                }
+                };
             }
+            libc::munmap(ptr, len);
         }
+        Err(())
     }
 
     /// SAFETY: `ptr` must have been allocated by `allocate_executable_memory` and point to `cap` bytes of memory
     pub(crate) unsafe fn dealloc_executable_memory(ptr: *mut u8, cap: usize) {
         libc::munmap(ptr as *mut _, cap);
     }
+
+    /// Try to grow `ptr` (currently `old_cap` bytes) to `new_cap` bytes in place, without copying.
+    ///
+    /// On Linux this uses `mremap(MREMAP_MAYMOVE)`, which lets the kernel extend the mapping
+    /// without a copy when there happens to be free address space after it, and otherwise moves
+    /// the mapping itself instead of leaving that to the caller. Returns `None` if the platform
+    /// has no such facility (e.g. macOS) or the remap fails, in which case the caller should fall
+    /// back to allocating a new region and copying.
+    ///
+    /// SAFETY: `ptr` must have been allocated by `alloc_executable_memory` or
+    /// `alloc_writable_memory` and point to `old_cap` bytes of memory.
+    #[cfg(target_os = "linux")]
+    pub(crate) unsafe fn remap_executable_memory(
+        ptr: *mut u8,
+        old_cap: usize,
+        new_cap: usize,
+    ) -> Option<NonNull<[u8]>> {
+        let new_ptr = libc::mremap(ptr as *mut _, old_cap, new_cap, libc::MREMAP_MAYMOVE);
+        if new_ptr == libc::MAP_FAILED {
+            None
+        } else {
+            NonNull::new(new_ptr.cast()).map(|ptr| NonNull::slice_from_raw_parts(ptr, new_cap))
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) unsafe fn remap_executable_memory(
+        _ptr: *mut u8,
+        _old_cap: usize,
+        _new_cap: usize,
+    ) -> Option<NonNull<[u8]>> {
+        None
+    }
 }
 
 #[cfg(target_os = "windows")]
@@ -86,14 +396,32 @@ mod windows {
         }
     }
 
-    pub(crate) fn alloc_executable_memory(desired: usize) -> Result<NonNull<[u8]>, ()> {
-        let actual = round_to(desired, page_size());
+    #[cfg(feature = "rwx")]
+    pub(super) fn alloc_executable_memory(desired: usize) -> Result<NonNull<[u8]>, ()> {
+        virtual_alloc(desired, winapi::um::winnt::PAGE_EXECUTE_READWRITE)
+    }
+
+    pub(super) fn alloc_writable_memory(desired: usize) -> Result<NonNull<[u8]>, ()> {
+        virtual_alloc(desired, winapi::um::winnt::PAGE_READWRITE)
+    }
+
+    pub(super) fn alloc_writable_memory_near(desired: usize, target: *const u8) -> Result<NonNull<[u8]>, ()> {
+        virtual_alloc_near(desired, winapi::um::winnt::PAGE_READWRITE, target)
+    }
+
+    #[cfg(feature = "rwx")]
+    pub(super) fn alloc_executable_memory_near(desired: usize, target: *const u8) -> Result<NonNull<[u8]>, ()> {
+        virtual_alloc_near(desired, winapi::um::winnt::PAGE_EXECUTE_READWRITE, target)
+    }
+
+    fn virtual_alloc(desired: usize, protect: winapi::shared::minwindef::DWORD) -> Result<NonNull<[u8]>, ()> {
+        let actual = try_round_to(desired, page_size())?;
         let raw_addr = unsafe {
             winapi::um::memoryapi::VirtualAlloc(
                 ptr::null_mut(),
                 actual,
                 winapi::um::winnt::MEM_RESERVE | winapi::um::winnt::MEM_COMMIT,
-                winapi::um::winnt::PAGE_EXECUTE_READWRITE,
+                protect,
             )
         };
 
@@ -103,8 +431,71 @@ mod windows {
         }
     }
 
+    /// `VirtualAlloc` with an address hint near `target`, retrying at nearby page-aligned
+    /// addresses (since Windows is free to ignore the hint and place the mapping wherever it
+    /// likes) until the result lands within [`in_near_range`] of `target`.
+    fn virtual_alloc_near(
+        desired: usize,
+        protect: winapi::shared::minwindef::DWORD,
+        target: *const u8,
+    ) -> Result<NonNull<[u8]>, ()> {
+        let actual = try_round_to(desired, page_size())?;
+        let page = page_size();
+        let target = target as usize;
+        for attempt in 0..NEAR_ALLOC_ATTEMPTS {
+            let hint = near_candidate(target, page, attempt);
+            let raw_addr = unsafe {
+                winapi::um::memoryapi::VirtualAlloc(
+                    hint as *mut _,
+                    actual,
+                    winapi::um::winnt::MEM_RESERVE | winapi::um::winnt::MEM_COMMIT,
+                    protect,
+                )
+            };
+            if raw_addr.is_null() {
+                continue;
+            }
+            if in_near_range(raw_addr as usize, actual, target) {
+                return match NonNull::new(raw_addr.cast()) {
+                    Some(ptr) => Ok(NonNull::slice_from_raw_parts(ptr, actual)),
+                    None => Err(()),
+                };
+            }
+            unsafe {
+                winapi::um::memoryapi::VirtualFree(raw_addr, 0, winapi::um::winnt::MEM_RELEASE);
+            }
+        }
+        Err(())
+    }
+
+    /// SAFETY: `ptr` must point to `cap` bytes previously mapped by `alloc_writable_memory`.
+    pub(super) unsafe fn seal_executable(ptr: *mut u8, cap: usize) -> Result<(), ()> {
+        let mut old_protect = 0;
+        let ok = winapi::um::memoryapi::VirtualProtect(
+            ptr as *mut _,
+            cap,
+            winapi::um::winnt::PAGE_EXECUTE_READ,
+            &mut old_protect,
+        );
+        if ok != 0 {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
     /// SAFETY: `ptr` must be non-null and come from an allocation returned by `alloc_executable_memory`.`
     pub(crate) unsafe fn dealloc_executable_memory(ptr: *mut u8, _: usize) {
         winapi::um::memoryapi::VirtualFree(ptr as *mut _, 0, winapi::um::winnt::MEM_RELEASE);
     }
+
+    /// Windows has no in-place remap facility for `VirtualAlloc` regions, so growing always
+    /// falls back to allocating a new region and copying.
+    pub(crate) unsafe fn remap_executable_memory(
+        _ptr: *mut u8,
+        _old_cap: usize,
+        _new_cap: usize,
+    ) -> Option<NonNull<[u8]>> {
+        None
+    }
 }
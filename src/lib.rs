@@ -5,15 +5,28 @@
 //!
 //! The Rust Abstract Machine is clueless about the fact that
 //! memory can be executable so i'm off the hook
+//!
+//! # `rwx` feature
+//!
+//! By default, [`ExecutableMemory`] never maps memory read+write+execute at once: pages are
+//! writable-only until sealed read+execute (see [`WritableMemory`]), since hardened platforms
+//! (Apple Silicon/iOS, OpenBSD, SELinux/PaX, hardened Android) reject RWX mappings outright.
+//! Enable the `rwx` feature to restore the old unconditional-RWX behavior, including mutating
+//! an [`ExecutableMemory`] directly (`push`/`reserve`/`resize`/`DerefMut`), on platforms that
+//! still allow it. `ExecutableAllocator` (behind `nightly`) always maps RWX and additionally
+//! requires this feature, since it has no seal step to make W^X workable.
 
 mod exec_alloc;
 
 mod bad_vec;
-pub use self::bad_vec::ExecutableMemory;
+pub use self::bad_vec::{AllocError, ExecutableMemory, WritableMemory};
 
-#[cfg(feature = "nightly")]
+// NOTE: `ExecutableAllocator` grows its backing pages incrementally via `Vec`'s push/grow, so
+// there's no single point to seal it read+execute the way `WritableMemory::make_executable`
+// does. It only makes sense as an unconditional RWX mapping, so it requires `rwx` too.
+#[cfg(all(feature = "nightly", feature = "rwx"))]
 pub use alloc_api::{ExecutableAllocator, Vec};
-#[cfg(feature = "nightly")]
+#[cfg(all(feature = "nightly", feature = "rwx"))]
 mod alloc_api {
     extern crate alloc;
 
@@ -43,12 +56,14 @@ mod alloc_api {
             &self,
             layout: core::alloc::Layout,
         ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
-            assert!(layout.align() <= exec_alloc::page_size());
-            exec_alloc::alloc_executable_memory(layout.size()).or(Err(AllocError))
+            // `alloc_executable_memory_aligned` over-allocates and rounds up when
+            // `layout.align()` exceeds the page size, so alignments beyond a page (e.g. for
+            // huge-page-backed JIT caches) work too.
+            exec_alloc::alloc_executable_memory_aligned(layout.size(), layout.align()).or(Err(AllocError))
         }
 
         unsafe fn deallocate(&self, ptr: core::ptr::NonNull<u8>, layout: core::alloc::Layout) {
-            exec_alloc::dealloc_executable_memory(ptr.as_ptr(), layout.size());
+            exec_alloc::dealloc_executable_memory_aligned(ptr.as_ptr(), layout.size(), layout.align());
         }
     }
 }